@@ -0,0 +1,42 @@
+//
+// avail.rs -- free-space ("avail") list element
+//
+// Copyright (c) 2019-2024 Jeff Garzik
+//
+// This file is part of the gdbm-native software project covered under
+// the MIT License.  For the full license text, please see the LICENSE
+// file in the root directory of this project.
+// SPDX-License-Identifier: MIT
+
+use std::io;
+
+use crate::ser::{r32, roff_t, w32, woff_t, FromReader, Layout, ToWriter};
+use crate::storage::Storage;
+
+/// A single free-space record: `size` bytes of unused space starting
+/// at `address`, as tracked by a bucket's (or the header's) avail
+/// list.
+#[derive(Debug, Clone, Copy)]
+pub struct AvailElem {
+    pub size: u32,
+    pub address: u64,
+}
+
+impl FromReader for AvailElem {
+    fn from_reader(rdr: &mut impl Storage, layout: &Layout) -> io::Result<Self> {
+        let size = r32(rdr, layout)?;
+        let address = roff_t(rdr, layout)?;
+
+        Ok(AvailElem { size, address })
+    }
+}
+
+impl ToWriter for AvailElem {
+    fn to_writer(&self, layout: &Layout) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.append(&mut w32(layout, self.size));
+        buf.append(&mut woff_t(layout, self.address));
+
+        buf
+    }
+}
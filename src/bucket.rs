@@ -1,8 +1,8 @@
-use byteorder::{LittleEndian, ReadBytesExt};
 use std::collections::HashMap;
-use std::io::{self, Error, ErrorKind, Read};
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom};
 
-use crate::ser::{w32, woff_t};
+use crate::ser::{r32, roff_t, w32, woff_t, ChecksumOptions, FromReader, Layout, ToWriter};
+use crate::storage::Storage;
 use crate::{AvailElem, Header, KEY_SMALL};
 
 pub const BUCKET_AVAIL: u32 = 6;
@@ -16,22 +16,17 @@ pub struct BucketElement {
     pub data_size: u32,
 }
 
-impl BucketElement {
-    pub fn from_reader(is_lfs: bool, rdr: &mut impl Read) -> io::Result<Self> {
-        let hash = rdr.read_u32::<LittleEndian>()?;
+impl FromReader for BucketElement {
+    fn from_reader(rdr: &mut impl Storage, layout: &Layout) -> io::Result<Self> {
+        let hash = r32(rdr, layout)?;
 
         let mut key_start = [0; KEY_SMALL];
         rdr.read(&mut key_start)?;
 
-        let data_ofs: u64;
-        if is_lfs {
-            data_ofs = rdr.read_u64::<LittleEndian>()?;
-        } else {
-            data_ofs = rdr.read_u32::<LittleEndian>()? as u64;
-        }
+        let data_ofs = roff_t(rdr, layout)?;
 
-        let key_size = rdr.read_u32::<LittleEndian>()?;
-        let data_size = rdr.read_u32::<LittleEndian>()?;
+        let key_size = r32(rdr, layout)?;
+        let data_size = r32(rdr, layout)?;
 
         Ok(BucketElement {
             hash,
@@ -41,14 +36,16 @@ impl BucketElement {
             data_size,
         })
     }
+}
 
-    pub fn serialize(&self, is_lfs: bool, is_le: bool) -> Vec<u8> {
+impl ToWriter for BucketElement {
+    fn to_writer(&self, layout: &Layout) -> Vec<u8> {
         let mut buf = Vec::new();
-        buf.append(&mut w32(is_le, self.hash));
+        buf.append(&mut w32(layout, self.hash));
         buf.append(&mut self.key_start.to_vec());
-        buf.append(&mut woff_t(is_lfs, is_le, self.data_ofs));
-        buf.append(&mut w32(is_le, self.key_size));
-        buf.append(&mut w32(is_le, self.data_size));
+        buf.append(&mut woff_t(layout, self.data_ofs));
+        buf.append(&mut w32(layout, self.key_size));
+        buf.append(&mut w32(layout, self.data_size));
 
         buf
     }
@@ -62,94 +59,225 @@ pub struct Bucket {
     pub bits: u32,
     pub count: u32,
     pub tab: Vec<BucketElement>,
+    // Raw value of the word directly following `av_count`: ordinary
+    // zero padding when checksums are disabled, or this bucket's
+    // stored CRC32 (non-LFS layout only -- see `ChecksumOptions`).
+    checksum_word: u32,
 }
 
-impl Bucket {
-    pub fn from_reader(header: &Header, rdr: &mut impl Read) -> io::Result<Self> {
+impl FromReader for Bucket {
+    fn from_reader(rdr: &mut impl Storage, layout: &Layout) -> io::Result<Self> {
         // read avail section
-        let av_count = rdr.read_u32::<LittleEndian>()?;
-        let _padding = rdr.read_u32::<LittleEndian>()?;
+        let av_count = r32(rdr, layout)?;
+        let checksum_word = r32(rdr, layout)?;
         let mut avail = Vec::new();
         for _idx in 0..BUCKET_AVAIL {
-            let av_elem = AvailElem::from_reader(header.is_lfs, rdr)?;
+            let av_elem = AvailElem::from_reader(rdr, layout)?;
             avail.push(av_elem);
         }
 
         // todo: validate and assure-sorted avail[]
 
         // read misc. section
-        let bits = rdr.read_u32::<LittleEndian>()?;
-        let count = rdr.read_u32::<LittleEndian>()?;
+        let bits = r32(rdr, layout)?;
+        let count = r32(rdr, layout)?;
+
+        Ok(Bucket {
+            av_count,
+            avail,
+            bits,
+            count,
+            tab: Vec::new(),
+            checksum_word,
+        })
+    }
+}
 
-        if !(count <= header.bucket_elems && bits <= header.dir_bits) {
+impl Bucket {
+    /// Reads a bucket per `header`'s layout, additionally validating
+    /// `count`/`bits` and reading the `bucket_elems`-sized element
+    /// table, both of which depend on `header` rather than on `Layout`
+    /// alone.  When `checksums.enabled`, also recomputes the CRC32
+    /// over the avail/misc/element sections and returns
+    /// `ErrorKind::InvalidData` ("bucket checksum mismatch at offset
+    /// N") on a mismatch; callers running in a read-only checker
+    /// should treat that as a reportable problem rather than fatal.
+    pub fn from_reader(
+        header: &Header,
+        rdr: &mut impl Storage,
+        checksums: ChecksumOptions,
+    ) -> io::Result<Self> {
+        let layout = Layout::from_header(header);
+        let start_ofs = rdr.seek(SeekFrom::Current(0))?;
+        let mut bucket = <Bucket as FromReader>::from_reader(rdr, &layout)?;
+
+        if !(bucket.count <= header.bucket_elems && bucket.bits <= header.dir_bits) {
             return Err(Error::new(ErrorKind::Other, "invalid bucket c/b"));
         }
 
         // read bucket elements section
         let mut tab = Vec::new();
         for _idx in 0..header.bucket_elems {
-            let bucket_elem = BucketElement::from_reader(header.is_lfs, rdr)?;
+            let bucket_elem = BucketElement::from_reader(rdr, &layout)?;
             tab.push(bucket_elem);
         }
+        bucket.tab = tab;
+
+        if checksums.enabled {
+            let stored = if layout.is_lfs() {
+                // The padding word stays reserved for LFS layouts (it
+                // keeps the avail[] array 8-byte aligned), so the CRC
+                // is appended as a trailing word instead.
+                r32(rdr, &layout)?
+            } else {
+                bucket.checksum_word
+            };
+
+            if stored != bucket.checksum(&layout) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bucket checksum mismatch at offset {start_ofs}"),
+                ));
+            }
+        }
 
-        Ok(Bucket {
-            av_count,
-            avail,
-            bits,
-            count,
-            tab,
-        })
+        Ok(bucket)
     }
 
-    pub fn serialize(&self, is_lfs: bool, is_le: bool) -> Vec<u8> {
-        let mut buf = Vec::new();
+    pub fn serialize(&self, header: &Header, checksums: ChecksumOptions) -> Vec<u8> {
+        let layout = Layout::from_header(header);
+        let mut buf = self.to_writer(&layout);
+
+        if checksums.enabled {
+            let crc = self.checksum(&layout);
+            if layout.is_lfs() {
+                buf.append(&mut w32(&layout, crc));
+            } else {
+                buf[4..8].copy_from_slice(&w32(&layout, crc));
+            }
+        }
 
-        //
-        // avail section
-        //
+        buf
+    }
+
+    // Bytes covered by the checksum: everything after `av_count` and
+    // its following word, i.e. avail[], bits/count, and the element
+    // table.
+    fn checksum_payload(&self, layout: &Layout) -> Vec<u8> {
+        let mut buf = Vec::new();
 
-        buf.append(&mut w32(is_le, self.av_count));
-        if is_lfs {
-            let padding: u32 = 0;
-            buf.append(&mut w32(is_le, padding));
+        // `from_reader` always reads exactly BUCKET_AVAIL entries, so
+        // the length here is a construction-time guarantee, not
+        // something that needs a defensive runtime assert.
+        for avail_elem in self.avail.iter().take(BUCKET_AVAIL as usize) {
+            buf.append(&mut avail_elem.to_writer(layout));
         }
 
-        assert_eq!(self.avail.len(), BUCKET_AVAIL as usize);
-        for avail_elem in &self.avail {
-            buf.append(&mut avail_elem.serialize(is_lfs, is_le));
+        buf.append(&mut w32(layout, self.bits));
+        buf.append(&mut w32(layout, self.count));
+
+        for bucket_elem in &self.tab {
+            buf.append(&mut bucket_elem.to_writer(layout));
         }
 
-        //
-        // misc section
-        //
-        buf.append(&mut w32(is_le, self.bits));
-        buf.append(&mut w32(is_le, self.count));
+        buf
+    }
+
+    fn checksum(&self, layout: &Layout) -> u32 {
+        crc32fast::hash(&self.checksum_payload(layout))
+    }
+}
+
+impl ToWriter for Bucket {
+    fn to_writer(&self, layout: &Layout) -> Vec<u8> {
+        let mut buf = Vec::new();
 
         //
-        // bucket elements section
+        // avail section
         //
-        for bucket_elem in &self.tab {
-            buf.append(&mut bucket_elem.serialize(is_lfs, is_le));
-        }
+
+        buf.append(&mut w32(layout, self.av_count));
+        // Reserved word: zero unless `Bucket::serialize` overwrites it
+        // with a checksum (see `ChecksumOptions`).
+        buf.append(&mut w32(layout, 0));
+
+        buf.append(&mut self.checksum_payload(layout));
 
         buf
     }
 }
 
+/// Tuning knobs for [`BucketCache`]'s eviction policy.
+///
+/// `max_buckets` and `max_bytes` are independent caps; the cache evicts
+/// once either is exceeded.  `None` disables that particular cap, so the
+/// default config (both `None`) is equivalent to the old unbounded cache.
+#[derive(Debug, Clone, Copy)]
+pub struct BucketCacheConfig {
+    pub max_buckets: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+impl Default for BucketCacheConfig {
+    fn default() -> Self {
+        BucketCacheConfig {
+            max_buckets: None,
+            max_bytes: None,
+        }
+    }
+}
+
+/// Running counters describing how effective the cache's eviction
+/// policy has been; useful for callers tuning [`BucketCacheConfig`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BucketCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+fn bucket_bytes(bucket: &Bucket) -> usize {
+    std::mem::size_of::<Bucket>()
+        + bucket.avail.len() * std::mem::size_of::<AvailElem>()
+        + bucket.tab.len() * std::mem::size_of::<BucketElement>()
+}
+
 #[derive(Debug)]
 pub struct BucketCache {
-    pub bucket_map: HashMap<u64, Bucket>,
+    bucket_map: HashMap<u64, Bucket>,
     pub dirty: HashMap<u64, bool>,
+    config: BucketCacheConfig,
+    stats: BucketCacheStats,
+    bytes_cached: usize,
+    // Recency counter per cached bucket offset: higher means more
+    // recently used.  A simple monotonic tick rather than an intrusive
+    // linked list, since the cache is not expected to hold enough
+    // entries for an O(n) eviction scan to matter.
+    recency: HashMap<u64, u64>,
+    tick: u64,
 }
 
 impl BucketCache {
     pub fn new() -> BucketCache {
+        BucketCache::with_config(BucketCacheConfig::default())
+    }
+
+    pub fn with_config(config: BucketCacheConfig) -> BucketCache {
         BucketCache {
             bucket_map: HashMap::new(),
             dirty: HashMap::new(),
+            config,
+            stats: BucketCacheStats::default(),
+            bytes_cached: 0,
+            recency: HashMap::new(),
+            tick: 0,
         }
     }
 
+    pub fn stats(&self) -> BucketCacheStats {
+        self.stats
+    }
+
     pub fn dirty(&mut self, bucket_ofs: u64) {
         self.dirty.insert(bucket_ofs, true);
     }
@@ -172,12 +300,166 @@ impl BucketCache {
         self.bucket_map.contains_key(&bucket_ofs)
     }
 
+    /// Look up a cached bucket without affecting hit/miss stats or
+    /// recency -- for callers (e.g. the dirty-flush path) that already
+    /// know the bucket is cached and aren't performing a cache lookup
+    /// in the sense [`BucketCacheStats`] tracks.
+    pub fn peek(&self, bucket_ofs: u64) -> Option<&Bucket> {
+        self.bucket_map.get(&bucket_ofs)
+    }
+
+    pub fn get(&mut self, bucket_ofs: u64) -> Option<&Bucket> {
+        let found = self.bucket_map.contains_key(&bucket_ofs);
+        if found {
+            self.touch(bucket_ofs);
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+
+        self.bucket_map.get(&bucket_ofs)
+    }
+
     pub fn insert(&mut self, bucket_ofs: u64, bucket: Bucket) {
-        self.bucket_map.insert(bucket_ofs, bucket);
+        self.bytes_cached += bucket_bytes(&bucket);
+        if let Some(prior) = self.bucket_map.insert(bucket_ofs, bucket) {
+            self.bytes_cached -= bucket_bytes(&prior);
+        }
+        self.touch(bucket_ofs);
+        self.evict_to_budget();
     }
 
     pub fn update(&mut self, bucket_ofs: u64, bucket: Bucket) {
-        self.bucket_map.insert(bucket_ofs, bucket);
+        // Mark dirty *before* inserting: `insert` may trigger eviction,
+        // and a bucket must never be evicted while it's listed dirty.
         self.dirty(bucket_ofs);
+        self.insert(bucket_ofs, bucket);
+    }
+
+    fn touch(&mut self, bucket_ofs: u64) {
+        self.tick += 1;
+        self.recency.insert(bucket_ofs, self.tick);
+    }
+
+    fn over_budget(&self) -> bool {
+        if let Some(max_buckets) = self.config.max_buckets {
+            if self.bucket_map.len() > max_buckets {
+                return true;
+            }
+        }
+        if let Some(max_bytes) = self.config.max_bytes {
+            if self.bytes_cached > max_bytes {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // Evict clean buckets, least-recently-used first, until back under
+    // budget or no clean bucket remains to evict.
+    fn evict_to_budget(&mut self) {
+        while self.over_budget() {
+            let victim = self
+                .recency
+                .iter()
+                .filter(|(ofs, _tick)| !self.dirty.contains_key(ofs))
+                .min_by_key(|(_ofs, tick)| **tick)
+                .map(|(ofs, _tick)| *ofs);
+
+            let Some(victim) = victim else {
+                break;
+            };
+
+            if let Some(bucket) = self.bucket_map.remove(&victim) {
+                self.bytes_cached -= bucket_bytes(&bucket);
+            }
+            self.recency.remove(&victim);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ser::{Alignment, Endian};
+    use std::io::Cursor;
+
+    fn test_bucket() -> Bucket {
+        Bucket {
+            av_count: 0,
+            avail: vec![AvailElem { size: 0, address: 0 }; BUCKET_AVAIL as usize],
+            bits: 0,
+            count: 0,
+            tab: Vec::new(),
+            checksum_word: 0,
+        }
+    }
+
+    fn test_header() -> Header {
+        Header {
+            endian: Endian::Little,
+            alignment: Alignment::Align32,
+            dir_ofs: 0,
+            dir_sz: 0,
+            bucket_elems: 0,
+            dir_bits: 0,
+        }
+    }
+
+    #[test]
+    fn evict_to_budget_never_evicts_a_dirty_bucket() {
+        let mut cache = BucketCache::with_config(BucketCacheConfig {
+            max_buckets: Some(2),
+            max_bytes: None,
+        });
+
+        cache.insert(1, test_bucket());
+        cache.insert(2, test_bucket());
+        cache.dirty(1);
+
+        // Pushes the cache one bucket over budget; the LRU victim among
+        // the *clean* entries is bucket 2, not the dirty bucket 1.
+        cache.insert(3, test_bucket());
+
+        assert!(cache.contains(1), "dirty bucket must never be evicted");
+        assert!(!cache.contains(2));
+        assert!(cache.contains(3));
+    }
+
+    #[test]
+    fn update_marks_dirty_before_eviction_can_run() {
+        let mut cache = BucketCache::with_config(BucketCacheConfig {
+            max_buckets: Some(1),
+            max_bytes: None,
+        });
+
+        cache.insert(1, test_bucket());
+        // update() must mark bucket 1 dirty before insert() can trigger
+        // eviction, or the only "clean" entry at that instant is the
+        // bucket being updated and it gets evicted out from under itself.
+        cache.update(1, test_bucket());
+
+        assert!(cache.contains(1));
+        assert!(cache.dirty_list().contains(&1));
+    }
+
+    #[test]
+    fn from_reader_rejects_checksum_mismatch() {
+        let header = test_header();
+        let bucket = test_bucket();
+        let mut bytes = bucket.serialize(&header, ChecksumOptions { enabled: true });
+
+        // Flip a byte within the checksummed payload (avail[0].size, just
+        // after `av_count` and the checksum word). Corrupting `bits` or
+        // `count` instead would trip the c/b validation before the
+        // checksum is ever compared.
+        bytes[8] ^= 0xff;
+
+        let mut storage = Cursor::new(bytes);
+        let err = Bucket::from_reader(&header, &mut storage, ChecksumOptions { enabled: true })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
     }
 }
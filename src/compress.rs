@@ -0,0 +1,275 @@
+//
+// compress.rs -- optional transparent compression for stored values
+//
+// Copyright (c) 2019-2024 Jeff Garzik
+//
+// This file is part of the gdbm-native software project covered under
+// the MIT License.  For the full license text, please see the LICENSE
+// file in the root directory of this project.
+// SPDX-License-Identifier: MIT
+
+use std::io::{self, Seek, Write};
+
+use crate::bucket::BucketElement;
+use crate::storage::Storage;
+
+/// Codec used to compress a single value's data record.
+///
+/// The variant actually available depends on which `compress-*` cargo
+/// feature was enabled at build time; `None` is always available and is
+/// what gets stored when a value doesn't shrink enough to be worth the
+/// decompression cost on read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    #[cfg(feature = "compress-zstd")]
+    Zstd,
+    #[cfg(feature = "compress-lzma")]
+    Lzma,
+    #[cfg(feature = "compress-bzip2")]
+    Bzip2,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            #[cfg(feature = "compress-zstd")]
+            Codec::Zstd => 1,
+            #[cfg(feature = "compress-lzma")]
+            Codec::Lzma => 2,
+            #[cfg(feature = "compress-bzip2")]
+            Codec::Bzip2 => 3,
+        }
+    }
+
+    fn from_tag(tag: u8) -> io::Result<Codec> {
+        match tag {
+            0 => Ok(Codec::None),
+            #[cfg(feature = "compress-zstd")]
+            1 => Ok(Codec::Zstd),
+            #[cfg(feature = "compress-lzma")]
+            2 => Ok(Codec::Lzma),
+            #[cfg(feature = "compress-bzip2")]
+            3 => Ok(Codec::Bzip2),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown value codec tag {other}"),
+            )),
+        }
+    }
+}
+
+/// Compression knobs surfaced on the database's open options.
+///
+/// `min_size` is the smallest payload, in bytes, worth attempting to
+/// compress at all; small values are stored raw (tag [`Codec::None`])
+/// since the codec framing would outweigh any saving.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionOptions {
+    pub codec: Codec,
+    pub min_size: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            codec: Codec::None,
+            min_size: 64,
+        }
+    }
+}
+
+fn compress_with(codec: Codec, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        #[cfg(feature = "compress-zstd")]
+        Codec::Zstd => zstd::encode_all(data, 0),
+        #[cfg(feature = "compress-lzma")]
+        Codec::Lzma => {
+            let mut out = Vec::new();
+            lzma_rs::lzma_compress(&mut io::Cursor::new(data), &mut out)?;
+            Ok(out)
+        }
+        #[cfg(feature = "compress-bzip2")]
+        Codec::Bzip2 => {
+            use std::io::Write;
+            let mut enc = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+            enc.write_all(data)?;
+            enc.finish()
+        }
+    }
+}
+
+fn decompress_with(codec: Codec, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        #[cfg(feature = "compress-zstd")]
+        Codec::Zstd => zstd::decode_all(data),
+        #[cfg(feature = "compress-lzma")]
+        Codec::Lzma => {
+            let mut out = Vec::new();
+            lzma_rs::lzma_decompress(&mut io::Cursor::new(data), &mut out)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            Ok(out)
+        }
+        #[cfg(feature = "compress-bzip2")]
+        Codec::Bzip2 => {
+            use std::io::Read;
+            let mut dec = bzip2::read::BzDecoder::new(data);
+            let mut out = Vec::new();
+            dec.read_to_end(&mut out)?;
+            Ok(out)
+        }
+    }
+}
+
+/// Encode a value's data record: a one-byte codec tag followed by the
+/// (possibly compressed) payload.  Values shorter than `opts.min_size`,
+/// or that don't actually shrink, fall back to `Codec::None` so reads
+/// never pay for a codec that didn't help.
+pub fn encode_record(opts: &CompressionOptions, data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < opts.min_size || opts.codec == Codec::None {
+        let mut out = Vec::with_capacity(data.len() + 1);
+        out.push(Codec::None.tag());
+        out.extend_from_slice(data);
+        return Ok(out);
+    }
+
+    let compressed = compress_with(opts.codec, data)?;
+    let (codec, body) = if compressed.len() < data.len() {
+        (opts.codec, compressed)
+    } else {
+        (Codec::None, data.to_vec())
+    };
+
+    let mut out = Vec::with_capacity(body.len() + 1);
+    out.push(codec.tag());
+    out.extend(body);
+    Ok(out)
+}
+
+/// Decode a data record previously written by [`encode_record`], reading
+/// the leading codec tag to decide whether (and how) to decompress.
+pub fn decode_record(record: &[u8]) -> io::Result<Vec<u8>> {
+    let (&tag, body) = record.split_first().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "empty value data record")
+    })?;
+
+    let codec = Codec::from_tag(tag)?;
+    decompress_with(codec, body)
+}
+
+/// Write `data` to `elem.data_ofs`, compressing it per `opts` first.
+///
+/// `capacity` is the number of bytes actually allocated at `data_ofs`
+/// (the prior record's size when overwriting in place, or a freshly
+/// allocated extent's size for a new record) -- the caller, not this
+/// function, owns the allocator and knows how much space is actually
+/// safe to write into. If the encoded record doesn't fit, this returns
+/// `ErrorKind::InvalidInput` rather than writing past `capacity` and
+/// corrupting whatever follows it in the file; the caller should
+/// allocate a larger extent and retry.
+///
+/// `elem.key_size`/`elem.key_start`/`elem.hash` are untouched -- only
+/// `data_size` is updated, to the on-disk record's actual (possibly
+/// compressed) length, so the bucket's bookkeeping matches what was
+/// written and lookups are unaffected.
+pub fn write_value(
+    storage: &mut (impl Storage + Write),
+    elem: &mut BucketElement,
+    opts: &CompressionOptions,
+    data: &[u8],
+    capacity: u32,
+) -> io::Result<()> {
+    let record = encode_record(opts, data)?;
+    if record.len() > capacity as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "encoded record ({} bytes) does not fit allocated capacity ({capacity} bytes)",
+                record.len()
+            ),
+        ));
+    }
+    storage.seek(io::SeekFrom::Start(elem.data_ofs))?;
+    storage.write_all(&record)?;
+    elem.data_size = record.len() as u32;
+
+    Ok(())
+}
+
+/// Read and transparently decompress the value `elem` points at.
+pub fn read_value(storage: &mut impl Storage, elem: &BucketElement) -> io::Result<Vec<u8>> {
+    let mut record = vec![0u8; elem.data_size as usize];
+    storage.read_at(elem.data_ofs, &mut record)?;
+
+    decode_record(&record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn test_elem() -> BucketElement {
+        BucketElement {
+            hash: 0,
+            key_start: [0; 4],
+            data_ofs: 0,
+            key_size: 0,
+            data_size: 0,
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip_uncompressed() {
+        let opts = CompressionOptions {
+            codec: Codec::None,
+            min_size: 0,
+        };
+        let data = b"hello world".to_vec();
+        let record = encode_record(&opts, &data).unwrap();
+        assert_eq!(record[0], Codec::None.tag());
+        assert_eq!(decode_record(&record).unwrap(), data);
+    }
+
+    #[cfg(feature = "compress-zstd")]
+    #[test]
+    fn encode_record_falls_back_to_none_when_compression_does_not_help() {
+        let opts = CompressionOptions {
+            codec: Codec::Zstd,
+            min_size: 0,
+        };
+        // Too short for zstd's frame overhead to pay off.
+        let data = vec![1u8, 2, 3];
+        let record = encode_record(&opts, &data).unwrap();
+        assert_eq!(record[0], Codec::None.tag());
+        assert_eq!(decode_record(&record).unwrap(), data);
+    }
+
+    #[test]
+    fn write_value_then_read_value_round_trips() {
+        let mut storage = Cursor::new(vec![0u8; 64]);
+        let mut elem = test_elem();
+        let opts = CompressionOptions::default();
+        let data = b"round trip value".to_vec();
+
+        write_value(&mut storage, &mut elem, &opts, &data, 64).unwrap();
+        assert_eq!(read_value(&mut storage, &elem).unwrap(), data);
+    }
+
+    #[test]
+    fn write_value_rejects_a_record_that_does_not_fit_capacity() {
+        let mut storage = Cursor::new(vec![0u8; 64]);
+        let mut elem = test_elem();
+        let opts = CompressionOptions {
+            codec: Codec::None,
+            min_size: 0,
+        };
+        let data = vec![0u8; 32];
+
+        let err = write_value(&mut storage, &mut elem, &opts, &data, 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
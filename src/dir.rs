@@ -8,10 +8,10 @@
 // file in the root directory of this project.
 // SPDX-License-Identifier: MIT
 
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
-use std::io::{self, Seek, SeekFrom};
+use std::io::{self, Error, ErrorKind, Seek, SeekFrom};
 
-use crate::ser::{woff_t, Alignment, Endian};
+use crate::ser::{r32, roff_t, w32, woff_t, Alignment, ChecksumOptions, Layout, ToWriter};
+use crate::storage::Storage;
 use crate::{Header, GDBM_HASH_BITS};
 
 pub fn build_dir_size(block_sz: u32) -> (u32, u32) {
@@ -37,11 +37,26 @@ impl Directory {
         self.dir.len()
     }
 
-    pub fn serialize(&self, alignment: Alignment, endian: Endian) -> Vec<u8> {
+    pub fn serialize(&self, header: &Header) -> Vec<u8> {
+        self.to_writer(&Layout::from_header(header))
+    }
+
+    /// CRC32 over the directory's serialized entries, mirroring
+    /// `Bucket::checksum`. Stored alongside the directory (see
+    /// [`dir_reader_checked`]/[`dir_writer_checked`]), not inline in
+    /// an on-disk field, since the classic gdbm directory format has
+    /// no spare word to reuse the way a bucket's padding word is used.
+    pub fn checksum(&self, header: &Header) -> u32 {
+        crc32fast::hash(&self.to_writer(&Layout::from_header(header)))
+    }
+}
+
+impl ToWriter for Directory {
+    fn to_writer(&self, layout: &Layout) -> Vec<u8> {
         let mut buf = Vec::new();
 
         for ofs in &self.dir {
-            buf.append(&mut woff_t(alignment, endian, *ofs));
+            buf.append(&mut woff_t(layout, *ofs));
         }
 
         buf
@@ -55,27 +70,10 @@ pub fn dirent_elem_size(alignment: Alignment) -> usize {
     }
 }
 
-fn roff_t(f: &mut std::fs::File, alignment: Alignment, endian: Endian) -> io::Result<u64> {
-    let v;
-
-    if endian == Endian::Little {
-        if alignment == Alignment::Align64 {
-            v = f.read_u64::<LittleEndian>()?;
-        } else {
-            v = f.read_u32::<LittleEndian>()? as u64;
-        }
-    } else if alignment == Alignment::Align64 {
-        v = f.read_u64::<BigEndian>()?;
-    } else {
-        v = f.read_u32::<BigEndian>()? as u64;
-    }
-
-    Ok(v)
-}
-
 // Read C-struct-based bucket directory (a vector of storage offsets)
-pub fn dir_reader(f: &mut std::fs::File, header: &Header) -> io::Result<Vec<u64>> {
-    let dirent_count = header.dir_sz as usize / dirent_elem_size(header.alignment);
+pub fn dir_reader(f: &mut impl Storage, header: &Header) -> io::Result<Vec<u64>> {
+    let layout = Layout::from_header(header);
+    let dirent_count = header.dir_sz as usize / dirent_elem_size(layout.alignment);
 
     let mut dir = Vec::new();
     dir.reserve_exact(dirent_count);
@@ -83,9 +81,51 @@ pub fn dir_reader(f: &mut std::fs::File, header: &Header) -> io::Result<Vec<u64>
     let _pos = f.seek(SeekFrom::Start(header.dir_ofs))?;
 
     for _idx in 0..dirent_count {
-        let ofs = roff_t(f, header.alignment, header.endian)?;
+        let ofs = roff_t(f, &layout)?;
         dir.push(ofs);
     }
 
     Ok(dir)
 }
+
+/// Like [`dir_reader`], but when `checksums.enabled` also reads the
+/// CRC32 word immediately following the directory's `dir_sz` bytes
+/// and verifies it against [`Directory::checksum`], returning
+/// `ErrorKind::InvalidData` ("directory checksum mismatch") on a
+/// mismatch.
+pub fn dir_reader_checked(
+    f: &mut impl Storage,
+    header: &Header,
+    checksums: ChecksumOptions,
+) -> io::Result<Directory> {
+    let dir = Directory {
+        dir: dir_reader(f, header)?,
+    };
+
+    if checksums.enabled {
+        let trailer_ofs = header
+            .dir_ofs
+            .checked_add(header.dir_sz as u64)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "directory offset/size overflows"))?;
+        let layout = Layout::from_header(header);
+        f.seek(SeekFrom::Start(trailer_ofs))?;
+        let stored = r32(f, &layout)?;
+        if stored != dir.checksum(header) {
+            return Err(Error::new(ErrorKind::InvalidData, "directory checksum mismatch"));
+        }
+    }
+
+    Ok(dir)
+}
+
+/// Serialize `dir`, appending a trailing CRC32 word when
+/// `checksums.enabled` -- the counterpart `dir_reader_checked` reads.
+pub fn dir_writer_checked(dir: &Directory, header: &Header, checksums: ChecksumOptions) -> Vec<u8> {
+    let mut buf = dir.serialize(header);
+
+    if checksums.enabled {
+        buf.append(&mut w32(&Layout::from_header(header), dir.checksum(header)));
+    }
+
+    buf
+}
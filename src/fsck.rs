@@ -0,0 +1,369 @@
+//
+// fsck.rs -- structural integrity checker for directory, buckets and avail lists
+//
+// Copyright (c) 2019-2024 Jeff Garzik
+//
+// This file is part of the gdbm-native software project covered under
+// the MIT License.  For the full license text, please see the LICENSE
+// file in the root directory of this project.
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::bucket::{Bucket, BUCKET_AVAIL};
+use crate::dir::Directory;
+use crate::ser::{r32, ChecksumOptions, Layout};
+use crate::storage::Storage;
+use crate::{Header, KEY_SMALL};
+
+/// A single inconsistency found while checking the database.
+///
+/// `bucket_ofs` is `None` for problems that don't belong to one
+/// specific bucket (e.g. directory-wide issues).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub bucket_ofs: Option<u64>,
+    pub slot: Option<usize>,
+    pub description: String,
+}
+
+impl Problem {
+    fn new(bucket_ofs: Option<u64>, slot: Option<usize>, description: impl Into<String>) -> Self {
+        Problem {
+            bucket_ofs,
+            slot,
+            description: description.into(),
+        }
+    }
+}
+
+/// The result of [`check`]: every inconsistency found, in the order
+/// they were discovered.  An empty report means the database looks
+/// structurally sound.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Block alignment used to sanity-check directory/bucket offsets.
+const BLOCK_ALIGN: u64 = 512;
+
+fn bucket_mask(bits: u32) -> u32 {
+    if bits >= 32 {
+        u32::MAX
+    } else {
+        (1u32 << bits) - 1
+    }
+}
+
+/// Walk the whole database -- directory, buckets, and each bucket's
+/// avail list -- and report every inconsistency found, without
+/// mutating anything.
+///
+/// `checksums` must match the database's actual checksum open option:
+/// most databases are created with checksums disabled (they're opt-in,
+/// see chunk0-6), and a bucket's reserved word then holds leftover
+/// padding rather than a real CRC32, so verifying it unconditionally
+/// would misreport every bucket as corrupt.
+pub fn check(
+    header: &Header,
+    dir: &Directory,
+    storage: &mut impl Storage,
+    checksums: ChecksumOptions,
+) -> io::Result<Report> {
+    let mut report = Report::default();
+    let file_len = storage.seek(io::SeekFrom::End(0))?;
+
+    // (0) Directory checksum: mirrors the per-bucket checksum check
+    // below, comparing the trailing CRC32 word written by
+    // `dir::dir_writer_checked` against the directory's actual
+    // contents (see chunk0-6).
+    if checksums.enabled {
+        match header.dir_ofs.checked_add(header.dir_sz as u64) {
+            Some(trailer_ofs) => {
+                let layout = Layout::from_header(header);
+                storage.seek(io::SeekFrom::Start(trailer_ofs))?;
+                let stored = r32(storage, &layout)?;
+                if stored != dir.checksum(header) {
+                    report.problems.push(Problem::new(
+                        None,
+                        None,
+                        "directory checksum mismatch".to_string(),
+                    ));
+                }
+            }
+            None => report.problems.push(Problem::new(
+                None,
+                None,
+                "directory offset/size overflows".to_string(),
+            )),
+        }
+    }
+
+    // (1) Directory offsets: block-aligned, in bounds, and slots that
+    // share the top `bucket.bits` bits of their index must resolve to
+    // the same bucket (extendible hashing invariant).
+    let mut bucket_at: HashMap<u64, Bucket> = HashMap::new();
+    for (slot, &ofs) in dir.dir.iter().enumerate() {
+        if ofs % BLOCK_ALIGN != 0 {
+            report.problems.push(Problem::new(
+                None,
+                Some(slot),
+                format!("directory slot {slot} offset {ofs} is not block-aligned"),
+            ));
+            continue;
+        }
+        if ofs >= file_len {
+            report.problems.push(Problem::new(
+                None,
+                Some(slot),
+                format!("directory slot {slot} offset {ofs} is beyond end of file"),
+            ));
+            continue;
+        }
+
+        if !bucket_at.contains_key(&ofs) {
+            storage.seek(io::SeekFrom::Start(ofs))?;
+            // Checksum mismatches (when `checksums.enabled`) surface as
+            // a problem here rather than a fatal error -- the checker
+            // is read-only, so the most useful thing it can do with a
+            // corrupt bucket is report it and keep going.
+            match Bucket::from_reader(header, storage, checksums) {
+                Ok(bucket) => {
+                    bucket_at.insert(ofs, bucket);
+                }
+                Err(e) => {
+                    report.problems.push(Problem::new(
+                        Some(ofs),
+                        Some(slot),
+                        format!("failed to read bucket: {e}"),
+                    ));
+                    continue;
+                }
+            }
+        }
+    }
+
+    for (slot, &ofs) in dir.dir.iter().enumerate() {
+        let Some(bucket) = bucket_at.get(&ofs) else {
+            continue;
+        };
+        // First slot of the group sharing this slot's top `bucket.bits`
+        // bits (out of the directory's `dir_bits`).
+        let shift = header.dir_bits.saturating_sub(bucket.bits);
+        let sibling_slot = (slot >> shift) << shift;
+        if let Some(&sibling_ofs) = dir.dir.get(sibling_slot) {
+            if sibling_ofs != ofs {
+                report.problems.push(Problem::new(
+                    Some(ofs),
+                    Some(slot),
+                    format!(
+                        "directory slot {slot} and sibling slot {sibling_slot} should share \
+                         bucket (bits={}) but point at {} and {}",
+                        bucket.bits, ofs, sibling_ofs
+                    ),
+                ));
+            }
+        }
+    }
+
+    // (2)-(4): per-bucket checks, plus a global claimed-range set to
+    // catch double-allocation of data offsets across all buckets.
+    let mut claimed: Vec<(u64, u64)> = Vec::new();
+
+    for (&bucket_ofs, bucket) in &bucket_at {
+        if bucket.av_count > BUCKET_AVAIL {
+            report.problems.push(Problem::new(
+                Some(bucket_ofs),
+                None,
+                format!(
+                    "av_count {} exceeds BUCKET_AVAIL {}",
+                    bucket.av_count, BUCKET_AVAIL
+                ),
+            ));
+        }
+
+        let mut prev_size: Option<u32> = None;
+        let mut avail_ranges: Vec<(u64, u64)> = Vec::new();
+        for (idx, elem) in bucket.avail.iter().enumerate() {
+            if (idx as u32) < bucket.av_count {
+                if let Some(prev) = prev_size {
+                    if elem.size > prev {
+                        report.problems.push(Problem::new(
+                            Some(bucket_ofs),
+                            Some(idx),
+                            format!(
+                                "avail[{idx}] size {} is out of sorted order (prev {prev})",
+                                elem.size
+                            ),
+                        ));
+                    }
+                }
+                prev_size = Some(elem.size);
+                match elem.address.checked_add(elem.size as u64) {
+                    Some(end) => avail_ranges.push((elem.address, end)),
+                    None => report.problems.push(Problem::new(
+                        Some(bucket_ofs),
+                        Some(idx),
+                        format!("avail[{idx}] range overflows"),
+                    )),
+                }
+            }
+        }
+        for i in 0..avail_ranges.len() {
+            for j in (i + 1)..avail_ranges.len() {
+                if ranges_overlap(avail_ranges[i], avail_ranges[j]) {
+                    report.problems.push(Problem::new(
+                        Some(bucket_ofs),
+                        Some(i),
+                        format!("avail entries {i} and {j} overlap"),
+                    ));
+                }
+            }
+        }
+
+        let mask = bucket_mask(bucket.bits);
+        for (slot, elem) in bucket.tab.iter().enumerate() {
+            if elem.data_size == 0 {
+                continue; // empty slot
+            }
+
+            if elem.hash & mask != (slot as u32) & mask {
+                report.problems.push(Problem::new(
+                    Some(bucket_ofs),
+                    Some(slot),
+                    format!(
+                        "element {slot} hash {:#x} & bits({}) does not match its slot",
+                        elem.hash, bucket.bits
+                    ),
+                ));
+            }
+
+            let key_len = KEY_SMALL.min(elem.key_size as usize);
+            let mut key_buf = vec![0u8; key_len];
+            if storage.read_at(elem.data_ofs, &mut key_buf).is_ok()
+                && key_buf != elem.key_start[..key_len]
+            {
+                report.problems.push(Problem::new(
+                    Some(bucket_ofs),
+                    Some(slot),
+                    format!("element {slot}'s key_start does not match the stored key"),
+                ));
+            }
+
+            let Some(data_end) = elem.data_ofs.checked_add(elem.data_size as u64) else {
+                report.problems.push(Problem::new(
+                    Some(bucket_ofs),
+                    Some(slot),
+                    format!("element {slot}'s data range overflows"),
+                ));
+                continue;
+            };
+            let data_range = (elem.data_ofs, data_end);
+            for avail_range in &avail_ranges {
+                if ranges_overlap(data_range, *avail_range) {
+                    report.problems.push(Problem::new(
+                        Some(bucket_ofs),
+                        Some(slot),
+                        format!("element {slot}'s data range overlaps a free (avail) block"),
+                    ));
+                }
+            }
+
+            for &(claimed_ofs, claimed_end) in &claimed {
+                if ranges_overlap(data_range, (claimed_ofs, claimed_end)) {
+                    report.problems.push(Problem::new(
+                        Some(bucket_ofs),
+                        Some(slot),
+                        format!(
+                            "element {slot}'s data range [{}, {}) double-allocates \
+                             bytes already claimed elsewhere",
+                            data_range.0, data_range.1
+                        ),
+                    ));
+                }
+            }
+            claimed.push(data_range);
+        }
+    }
+
+    Ok(report)
+}
+
+fn ranges_overlap(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ser::{w32, woff_t, Endian};
+    use std::io::Cursor;
+
+    fn test_header() -> Header {
+        Header {
+            endian: Endian::Little,
+            alignment: crate::ser::Alignment::Align32,
+            dir_ofs: 0,
+            dir_sz: 0,
+            bucket_elems: 0,
+            dir_bits: 0,
+        }
+    }
+
+    // Hand-assembled raw bucket bytes (av_count, reserved word, exactly
+    // BUCKET_AVAIL avail entries, bits, count) -- built directly with
+    // `ser` primitives rather than `Bucket`'s own (de)serialization, so
+    // this exercises `check()` against bytes it didn't produce itself.
+    fn raw_bucket_bytes(layout: &Layout, av_count: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(w32(layout, av_count));
+        buf.extend(w32(layout, 0)); // reserved/checksum word
+        for _ in 0..BUCKET_AVAIL {
+            buf.extend(w32(layout, 0)); // avail[].size
+            buf.extend(woff_t(layout, 0)); // avail[].address
+        }
+        buf.extend(w32(layout, 0)); // bits
+        buf.extend(w32(layout, 0)); // count
+        buf
+    }
+
+    #[test]
+    fn check_flags_misaligned_directory_slot() {
+        let header = test_header();
+        let dir = Directory { dir: vec![1] };
+        let mut storage = Cursor::new(vec![0u8; 512]);
+
+        let report = check(&header, &dir, &mut storage, ChecksumOptions::default()).unwrap();
+
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| p.description.contains("not block-aligned")));
+    }
+
+    #[test]
+    fn check_flags_bucket_av_count_exceeding_capacity() {
+        let header = test_header();
+        let layout = Layout::from_header(&header);
+        let bytes = raw_bucket_bytes(&layout, BUCKET_AVAIL + 1);
+
+        let mut storage = Cursor::new(vec![0u8; 512.max(bytes.len())]);
+        storage.get_mut()[..bytes.len()].copy_from_slice(&bytes);
+
+        let dir = Directory { dir: vec![0] };
+        let report = check(&header, &dir, &mut storage, ChecksumOptions::default()).unwrap();
+
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| p.description.contains("exceeds BUCKET_AVAIL")));
+    }
+}
@@ -0,0 +1,110 @@
+//
+// ser.rs -- shared on-disk (de)serialization primitives
+//
+// Copyright (c) 2019-2024 Jeff Garzik
+//
+// This file is part of the gdbm-native software project covered under
+// the MIT License.  For the full license text, please see the LICENSE
+// file in the root directory of this project.
+// SPDX-License-Identifier: MIT
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use std::io;
+
+use crate::storage::Storage;
+use crate::Header;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Align32,
+    Align64,
+}
+
+/// The byte-order and offset-width a gdbm database was written with.
+///
+/// Every on-disk type in this crate reads and writes through a
+/// `Layout`, so there is exactly one place that decides "how wide is
+/// an offset" and "which end is first" -- as opposed to the old
+/// `is_lfs`/`is_le` bools threaded separately from the directory's
+/// `Alignment`/`Endian`, which could (and did) disagree with the
+/// header's actual layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub endian: Endian,
+    pub alignment: Alignment,
+}
+
+impl Layout {
+    pub fn new(endian: Endian, alignment: Alignment) -> Self {
+        Layout { endian, alignment }
+    }
+
+    pub fn from_header(header: &Header) -> Self {
+        Layout {
+            endian: header.endian,
+            alignment: header.alignment,
+        }
+    }
+
+    pub fn is_lfs(&self) -> bool {
+        self.alignment == Alignment::Align64
+    }
+}
+
+/// Whether an on-disk structure (a [`crate::bucket::Bucket`] or the
+/// [`crate::dir::Directory`]) carries a CRC32 alongside its regular
+/// contents, mirroring the checksum open option a database is opened
+/// with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChecksumOptions {
+    pub enabled: bool,
+}
+
+/// Read `Self` from a storage backend, interpreting multi-byte fields
+/// according to `layout`.
+pub trait FromReader: Sized {
+    fn from_reader(rdr: &mut impl Storage, layout: &Layout) -> io::Result<Self>;
+}
+
+/// Serialize `Self` to its on-disk byte representation under `layout`.
+pub trait ToWriter {
+    fn to_writer(&self, layout: &Layout) -> Vec<u8>;
+}
+
+pub fn w32(layout: &Layout, v: u32) -> Vec<u8> {
+    match layout.endian {
+        Endian::Little => v.to_le_bytes().to_vec(),
+        Endian::Big => v.to_be_bytes().to_vec(),
+    }
+}
+
+pub fn r32(rdr: &mut impl Storage, layout: &Layout) -> io::Result<u32> {
+    match layout.endian {
+        Endian::Little => rdr.read_u32::<LittleEndian>(),
+        Endian::Big => rdr.read_u32::<BigEndian>(),
+    }
+}
+
+pub fn woff_t(layout: &Layout, v: u64) -> Vec<u8> {
+    match (layout.endian, layout.alignment) {
+        (Endian::Little, Alignment::Align64) => v.to_le_bytes().to_vec(),
+        (Endian::Little, Alignment::Align32) => (v as u32).to_le_bytes().to_vec(),
+        (Endian::Big, Alignment::Align64) => v.to_be_bytes().to_vec(),
+        (Endian::Big, Alignment::Align32) => (v as u32).to_be_bytes().to_vec(),
+    }
+}
+
+pub fn roff_t(rdr: &mut impl Storage, layout: &Layout) -> io::Result<u64> {
+    match (layout.endian, layout.alignment) {
+        (Endian::Little, Alignment::Align64) => rdr.read_u64::<LittleEndian>(),
+        (Endian::Little, Alignment::Align32) => Ok(rdr.read_u32::<LittleEndian>()? as u64),
+        (Endian::Big, Alignment::Align64) => rdr.read_u64::<BigEndian>(),
+        (Endian::Big, Alignment::Align32) => Ok(rdr.read_u32::<BigEndian>()? as u64),
+    }
+}
@@ -0,0 +1,31 @@
+//
+// storage.rs -- generic, seekable storage backend for gdbm-native
+//
+// Copyright (c) 2019-2024 Jeff Garzik
+//
+// This file is part of the gdbm-native software project covered under
+// the MIT License.  For the full license text, please see the LICENSE
+// file in the root directory of this project.
+// SPDX-License-Identifier: MIT
+
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+/// A readable, seekable backing store for a gdbm database.
+///
+/// Implemented for `std::fs::File` so on-disk databases keep working
+/// unchanged, and for `Cursor<Vec<u8>>` so databases can be opened
+/// read-only from an in-memory byte buffer (handy for tests and for
+/// embedding a prebuilt database without touching the filesystem).
+pub trait Storage: Read + Seek {
+    /// Read `buf.len()` bytes starting at `offset`, restoring the prior
+    /// seek position is left up to the caller -- this is a convenience
+    /// wrapper, not a positional (pread-style) read.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+}
+
+impl Storage for File {}
+impl Storage for Cursor<Vec<u8>> {}